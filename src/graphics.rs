@@ -0,0 +1,77 @@
+//! `embedded-graphics` support, gated behind the `graphics` feature.
+//!
+//! This mirrors how the uc8151 and sh1106 drivers expose their displays to the
+//! `embedded-graphics` ecosystem: implement [`DrawTarget`] and
+//! [`OriginDimensions`] on top of the same frame buffer and dirty-line tracking
+//! that [`Display::set_pixel`] uses, so callers can
+//! use the standard drawing primitives instead of calling [`Display::set_pixel`]
+//! directly.
+
+use embedded_graphics::{
+    pixelcolor::BinaryColor,
+    prelude::*,
+    Pixel,
+};
+
+use crate::Display;
+use embedded_hal as hal;
+
+impl<
+        SPI,
+        CS,
+        DISP,
+        const WIDTH: usize,
+        const HEIGHT: usize,
+        const BUFFER_SIZE: usize,
+        const DIRTY_SIZE: usize,
+        const REFRESH_SIZE: usize,
+    > OriginDimensions for Display<SPI, CS, DISP, WIDTH, HEIGHT, BUFFER_SIZE, DIRTY_SIZE, REFRESH_SIZE>
+where
+    SPI: hal::blocking::spi::Write<u8>,
+    CS: hal::digital::v2::OutputPin,
+    DISP: hal::digital::v2::OutputPin,
+{
+    fn size(&self) -> Size {
+        Size::new(WIDTH as u32, HEIGHT as u32)
+    }
+}
+
+impl<
+        SPI,
+        CS,
+        DISP,
+        const WIDTH: usize,
+        const HEIGHT: usize,
+        const BUFFER_SIZE: usize,
+        const DIRTY_SIZE: usize,
+        const REFRESH_SIZE: usize,
+    > DrawTarget for Display<SPI, CS, DISP, WIDTH, HEIGHT, BUFFER_SIZE, DIRTY_SIZE, REFRESH_SIZE>
+where
+    SPI: hal::blocking::spi::Write<u8>,
+    CS: hal::digital::v2::OutputPin,
+    DISP: hal::digital::v2::OutputPin,
+{
+    type Color = BinaryColor;
+    type Error = core::convert::Infallible;
+
+    fn draw_iter<I>(&mut self, pixels: I) -> Result<(), Self::Error>
+    where
+        I: IntoIterator<Item = Pixel<Self::Color>>,
+    {
+        for Pixel(coord, color) in pixels {
+            if coord.x < 0 || coord.y < 0 {
+                continue;
+            }
+            let (x, y) = (coord.x as usize, coord.y as usize);
+            if x >= WIDTH || y >= HEIGHT {
+                continue;
+            }
+            // Same bit layout as `set_pixel`, but indexed with `usize` so
+            // columns past 255 (e.g. on the 400-wide LS027B7DH01) aren't truncated.
+            let into = y * WIDTH + x;
+            crate::set_masked(&mut self.buffer[into / 8], 1 << (into % 8), color == BinaryColor::On);
+            self.mark_dirty(y);
+        }
+        Ok(())
+    }
+}
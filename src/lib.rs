@@ -6,151 +6,337 @@
 #![deny(warnings)]
 #![no_std]
 
-use embedded_hal as hal;
+#[cfg(feature = "graphics")]
+mod graphics;
 
-const WIDTH: u8 = 144;
-const HEIGHT: u8 = 168;
-const BUFFER_SIZE: usize = WIDTH as usize * HEIGHT as usize / 8; //this works because 144*168 is dividable by 8 if the dimensions ever change make sure to handle this!
+use embedded_hal as hal;
 
 /// The Sharp Memory Display driver
-pub struct Display<SPI, CS>
-where
+///
+/// `WIDTH` and `HEIGHT` are the panel dimensions in pixels, `BUFFER_SIZE` must
+/// be `WIDTH * HEIGHT / 8` (one bit per pixel) and `DIRTY_SIZE` must be
+/// `HEIGHT / 8` (one bit per line, tracking which lines need to be
+/// re-transmitted on [`refresh`](Display::refresh)). Rust const generics
+/// don't yet let these be computed from `WIDTH` and `HEIGHT` automatically,
+/// so they have to be spelled out at the use site -- prefer one of the panel
+/// type aliases below instead of naming `Display` directly.
+///
+/// `DISP` is the (optional) display-enable pin, defaulting to [`NoPin`] when
+/// constructed via [`Display::new`]. There's no `EXTCOMIN` pin here: that
+/// signal is meant to be driven by a hardware timer/PWM channel wired up by
+/// the caller, outside of this driver -- see
+/// [`new_with_pins`](Display::new_with_pins) for disabling the in-command
+/// VCOM bit once that's in place.
+///
+/// `REFRESH_SIZE` must be `BUFFER_SIZE + 1 + HEIGHT * 2 + 1`, the worst-case
+/// size of the SPI transaction [`refresh`](Display::refresh) builds (command
+/// byte, plus address/data/end per line, plus a final terminating byte) --
+/// another consequence of const generics not letting derived consts be
+/// computed automatically.
+pub struct Display<
+    SPI,
+    CS,
+    DISP,
+    const WIDTH: usize,
+    const HEIGHT: usize,
+    const BUFFER_SIZE: usize,
+    const DIRTY_SIZE: usize,
+    const REFRESH_SIZE: usize,
+> where
     SPI: hal::blocking::spi::Write<u8>,
     CS: hal::digital::v2::OutputPin,
+    DISP: hal::digital::v2::OutputPin,
 {
     com: SPI,
     cs: CS,
+    disp: DISP,
     buffer: [u8; BUFFER_SIZE],
+    dirty: [u8; DIRTY_SIZE],
     vcom: bool,
+    software_vcom: bool,
+}
+
+/// Sharp LS013B7DH05, 144x168 pixels, as found on the Adafruit breakout.
+pub type Ls013b7dh05<SPI, CS> =
+    Display<SPI, CS, NoPin, 144, 168, { 144 * 168 / 8 }, { 168 / 8 }, 3362>;
+
+/// Sharp LS013B7DH03, 128x128 pixels.
+pub type Ls013b7dh03<SPI, CS> =
+    Display<SPI, CS, NoPin, 128, 128, { 128 * 128 / 8 }, { 128 / 8 }, 2306>;
+
+/// Sharp LS027B7DH01, 400x240 pixels.
+pub type Ls027b7dh01<SPI, CS> =
+    Display<SPI, CS, NoPin, 400, 240, { 400 * 240 / 8 }, { 240 / 8 }, 12482>;
+
+/// A no-op [`OutputPin`](hal::digital::v2::OutputPin), used as the `DISP`
+/// pin when a panel isn't wired up to one (the default for
+/// [`Display::new`]).
+pub struct NoPin;
+
+impl hal::digital::v2::OutputPin for NoPin {
+    type Error = core::convert::Infallible;
+
+    fn set_low(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    fn set_high(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
 }
 
-impl<SPI, CS, SpiError, IoError> Display<SPI, CS>
+impl<
+        SPI,
+        CS,
+        SpiError,
+        IoError,
+        const WIDTH: usize,
+        const HEIGHT: usize,
+        const BUFFER_SIZE: usize,
+        const DIRTY_SIZE: usize,
+        const REFRESH_SIZE: usize,
+    > Display<SPI, CS, NoPin, WIDTH, HEIGHT, BUFFER_SIZE, DIRTY_SIZE, REFRESH_SIZE>
 where
     SPI: hal::blocking::spi::Write<u8, Error = SpiError>,
     CS: hal::digital::v2::OutputPin<Error = IoError>,
 {
     ///Creates a new display driver
-    pub fn new(spi: SPI, cs: CS) -> Result<Display<SPI, CS>, ()> {
+    pub fn new(spi: SPI, cs: CS) -> Result<Self, Error<SpiError, IoError>> {
+        Self::new_with_pins(spi, cs, NoPin, true)
+    }
+}
+
+impl<
+        SPI,
+        CS,
+        DISP,
+        SpiError,
+        IoError,
+        const WIDTH: usize,
+        const HEIGHT: usize,
+        const BUFFER_SIZE: usize,
+        const DIRTY_SIZE: usize,
+        const REFRESH_SIZE: usize,
+    > Display<SPI, CS, DISP, WIDTH, HEIGHT, BUFFER_SIZE, DIRTY_SIZE, REFRESH_SIZE>
+where
+    SPI: hal::blocking::spi::Write<u8, Error = SpiError>,
+    CS: hal::digital::v2::OutputPin<Error = IoError>,
+    DISP: hal::digital::v2::OutputPin,
+{
+    /// Creates a new display driver with explicit control of the DISP pin.
+    ///
+    /// Set `software_vcom` to `false` when EXTCOMIN is wired to a timer or
+    /// PWM that drives the VCOM polarity inversion itself -- every command
+    /// then stops toggling the VCOM bit on every [`clear`](Display::clear)/
+    /// [`refresh`](Display::refresh), freeing the MCU from the strict >1Hz
+    /// cadence that's otherwise needed just to keep the panel's liquid
+    /// crystal from degrading.
+    pub fn new_with_pins(
+        spi: SPI,
+        cs: CS,
+        disp: DISP,
+        software_vcom: bool,
+    ) -> Result<Self, Error<SpiError, IoError>> {
+        debug_assert_eq!(BUFFER_SIZE, WIDTH * HEIGHT / 8);
+        debug_assert_eq!(DIRTY_SIZE, HEIGHT / 8);
+        debug_assert_eq!(REFRESH_SIZE, BUFFER_SIZE + 1 + HEIGHT * 2 + 1);
         let mut display = Display {
             com: spi,
-            cs: cs,
+            cs,
+            disp,
             buffer: [0; BUFFER_SIZE],
+            dirty: [0; DIRTY_SIZE],
             vcom: true,
+            software_vcom,
         };
-        let result = display.cs.set_low();
-        if result.is_err() {
-            return Err(());
-        }
+        display.cs.set_low().map_err(Error::Pin)?;
         display.clear()?;
         Ok(display)
     }
 
+    /// Turn the display on by driving the DISP pin high.
+    pub fn on(&mut self) -> Result<(), DISP::Error> {
+        self.disp.set_high()
+    }
+
+    /// Turn the display off by driving the DISP pin low.
+    pub fn off(&mut self) -> Result<(), DISP::Error> {
+        self.disp.set_low()
+    }
+
     /// Clear the display buffer and therefore the display
-    pub fn clear(&mut self)  -> Result<(), ()> {
+    pub fn clear(&mut self) -> Result<(), Error<SpiError, IoError>> {
         for val in self.buffer.iter_mut() {
             *val = 0;
         }
+        for val in self.dirty.iter_mut() {
+            *val = 0;
+        }
         //Chip select
-        if let Err(_) = self.cs.set_high() {
-            return Err(());
-        };
+        self.cs.set_high().map_err(Error::Pin)?;
         let command = self.command(CommandBit::ClearBit);
-        let mut failure = false;
-        if let Ok(_) = self.write_byte(command) {
-            if let Err(_) = self.write_byte(0) {
-                failure = true;
-            }
-        } else {
-            failure = true;
-        }
-        if let Err(_) = self.cs.set_low() {
-            return Err(());
-        };
-        if failure {
-            return Err(());
-        };
-        Ok(())
+        let result = self.write_byte(command).and_then(|_| self.write_byte(0));
+        self.cs.set_low().map_err(Error::Pin)?;
+        result
     }
 
     /// Refresh function. Should be called periodically with >1Hz to update display
-    pub fn refresh(&mut self) -> Result<(), ()> {
-        const SIZE: usize = BUFFER_SIZE + 1 + HEIGHT as usize * 2 + 1; //1 byte command, heigh * 2 byte per line (number, data, end), 1 byte end
-        let mut buffer: [u8; SIZE] = [0; SIZE];
+    ///
+    /// Only the lines marked dirty by [`set_pixel`](Display::set_pixel),
+    /// [`fill`](Display::fill) or [`fill_region`](Display::fill_region) since
+    /// the last call are transmitted ([`clear`](Display::clear) blanks the
+    /// panel directly and leaves no line dirty) -- the Sharp protocol
+    /// addresses each line independently, so a small update (e.g. a moving
+    /// sprite) turns into a short SPI transaction instead of a full repaint.
+    pub fn refresh(&mut self) -> Result<(), Error<SpiError, IoError>> {
+        // 1 byte command, per line 1 byte number + WIDTH / 8 data + 1 byte end, 1 byte end
+        let mut buffer = [0u8; REFRESH_SIZE];
         buffer[0] = self.command(CommandBit::WriteCmd);
-        const BYTES_PER_LINE: u8 = WIDTH / 8 + 2;
+        let bytes_per_line = WIDTH / 8;
+        let mut pos = 1;
         for i in 0..HEIGHT {
-            let buffer_index: usize = (i * BYTES_PER_LINE + 1) as usize;
-            buffer[buffer_index] = i + 1;
-            let slice_index: usize = (i * WIDTH / 8) as usize;
-            let slice = &self.buffer[slice_index..slice_index + WIDTH as usize / 8];
-            buffer[buffer_index + 1 .. buffer_index + WIDTH as usize / 8  - 1].copy_from_slice(&slice);
-            buffer[buffer_index + WIDTH as usize / 8] = 0;
+            if !self.is_dirty(i) {
+                continue;
+            }
+            buffer[pos] = i as u8 + 1;
+            pos += 1;
+            let slice_index: usize = i * bytes_per_line;
+            let slice = &self.buffer[slice_index..slice_index + bytes_per_line];
+            buffer[pos..pos + bytes_per_line].copy_from_slice(slice);
+            pos += bytes_per_line;
+            buffer[pos] = 0; // per-line trailing byte
+            pos += 1;
         }
-        
+        pos += 1; // trailing byte terminating the whole transmission
+
         //Chipselect
-        //TODO: better error handling
-        let _ = self.cs.set_high(); 
-        let _ = self.com.write(&buffer);    
-        let _ = self.cs.set_low();
-        
+        self.cs.set_high().map_err(Error::Pin)?;
+        let result = self.com.write(&buffer[..pos]).map_err(Error::Spi);
+        self.cs.set_low().map_err(Error::Pin)?;
+        result?;
+
+        for val in self.dirty.iter_mut() {
+            *val = 0;
+        }
+
         Ok(())
     }
 
     /// Set a pixel
     /// The pixel are numerated started from the top left starting with 0,0
     pub fn set_pixel(&mut self, x: u8, y: u8, black: bool) {
-        if x > WIDTH || y > HEIGHT {
+        if x as usize >= WIDTH || y as usize >= HEIGHT {
             return;
         }
-        let (index, bit) = get_index(x, y);
+        let (index, bit) = get_index::<WIDTH>(x, y);
         if black {
             self.buffer[index] |= 1 << bit;
         } else {
             self.buffer[index] &= !(1 << bit);
         }
+        self.mark_dirty(y as usize);
     }
 
     /// Get a pixel
     pub fn get_pixel(&mut self, x: u8, y: u8) -> Option<bool> {
-        if x > WIDTH || y > HEIGHT {
+        if x as usize >= WIDTH || y as usize >= HEIGHT {
             return None;
         }
-        let (index, bit) = get_index(x, y);
+        let (index, bit) = get_index::<WIDTH>(x, y);
         Some((self.buffer[index] & 1 << bit) != 0)
 
     }
 
+    /// Fill the whole display buffer, writing whole bytes instead of
+    /// looping pixel by pixel like repeatedly calling [`set_pixel`](Display::set_pixel) would.
+    pub fn fill(&mut self, black: bool) {
+        let value = if black { 0xFF } else { 0x00 };
+        for val in self.buffer.iter_mut() {
+            *val = value;
+        }
+        for val in self.dirty.iter_mut() {
+            *val = 0xFF;
+        }
+    }
+
+    /// Fill the rectangle `[x, x + w) x [y, y + h)`, clipped to the panel
+    /// bounds.
+    ///
+    /// Spans that cover whole bytes horizontally are `memset` with
+    /// `0x00`/`0xFF` in one go; only the partial bytes at the left and right
+    /// edges of each row are masked bit by bit, which is far faster than
+    /// looping over every pixel with [`set_pixel`](Display::set_pixel).
+    pub fn fill_region(&mut self, x: u8, y: u8, w: u8, h: u8, black: bool) {
+        let (x, y, w, h) = (x as usize, y as usize, w as usize, h as usize);
+        if x >= WIDTH || y >= HEIGHT || w == 0 || h == 0 {
+            return;
+        }
+        let x_end = (x + w).min(WIDTH);
+        let y_end = (y + h).min(HEIGHT);
+        let first_byte = x / 8;
+        let last_byte = (x_end - 1) / 8;
+        let full_value = if black { 0xFF } else { 0x00 };
+
+        for row in y..y_end {
+            let row_start = row * WIDTH / 8;
+            if first_byte == last_byte {
+                let mask = byte_mask(x % 8, x_end - x);
+                set_masked(&mut self.buffer[row_start + first_byte], mask, black);
+            } else {
+                let lead_mask = byte_mask(x % 8, 8 - x % 8);
+                set_masked(&mut self.buffer[row_start + first_byte], lead_mask, black);
+
+                for b in first_byte + 1..last_byte {
+                    self.buffer[row_start + b] = full_value;
+                }
+
+                let tail_mask = byte_mask(0, x_end - last_byte * 8);
+                set_masked(&mut self.buffer[row_start + last_byte], tail_mask, black);
+            }
+            self.mark_dirty(row);
+        }
+    }
+
     fn command(&mut self, command: CommandBit) -> u8 {
         let mut command = command as u8;
-        if self.vcom {
-            command |= 0x02;
+        if self.software_vcom {
+            if self.vcom {
+                command |= 0x02;
+            }
+            self.toggle_vcom();
         }
-        self.toggle_vcom();
         command
     }
 
-    fn write_byte(&mut self, data: u8) -> Result<(), ()> {
+    fn write_byte(&mut self, data: u8) -> Result<(), Error<SpiError, IoError>> {
         //First send the command bits
-        let result = self.com.write(&[data]);
-        if result.is_err() {
-            return Err(());
-        }
-        Ok(())
+        self.com.write(&[data]).map_err(Error::Spi)
     }
 
     fn toggle_vcom(&mut self) {
         self.vcom = !self.vcom;
     }
-}
 
-impl<E> From<E> for ()
-    where
-        E: hal::digital::v2::OutputPin::Error
-{
-    fn from(err: E) -> Self {
-        ()
+    fn mark_dirty(&mut self, line: usize) {
+        self.dirty[line / 8] |= 1 << (line % 8);
+    }
+
+    fn is_dirty(&self, line: usize) -> bool {
+        self.dirty[line / 8] & (1 << (line % 8)) != 0
     }
+}
 
+/// Error type returned by the fallible `Display` operations.
+///
+/// Wraps whichever underlying HAL error actually occurred instead of
+/// collapsing both into `()`, following the associated-error-type pattern
+/// the ili9341 driver uses.
+#[derive(Debug)]
+pub enum Error<SpiError, IoError> {
+    /// Writing to the SPI bus failed.
+    Spi(SpiError),
+    /// Toggling the chip-select pin failed.
+    Pin(IoError),
 }
 
 enum CommandBit {
@@ -158,9 +344,102 @@ enum CommandBit {
     ClearBit = 0x04,
 }
 
-fn get_index(x: u8, y: u8) -> (usize, u8) {
-    let into = y as usize * WIDTH as usize + x as usize;
+fn get_index<const WIDTH: usize>(x: u8, y: u8) -> (usize, u8) {
+    let into = y as usize * WIDTH + x as usize;
     let index = into / 8;
     let bit = into % 8;
     (index, bit as u8)
 }
+
+/// A mask covering `count` bits starting at bit `start` within a byte.
+fn byte_mask(start: usize, count: usize) -> u8 {
+    (((1u16 << count) - 1) << start) as u8
+}
+
+fn set_masked(byte: &mut u8, mask: u8, black: bool) {
+    if black {
+        *byte |= mask;
+    } else {
+        *byte &= !mask;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct MockSpi {
+        written: [u8; 64],
+        len: usize,
+    }
+
+    impl MockSpi {
+        fn new() -> Self {
+            MockSpi {
+                written: [0; 64],
+                len: 0,
+            }
+        }
+    }
+
+    impl hal::blocking::spi::Write<u8> for MockSpi {
+        type Error = core::convert::Infallible;
+
+        fn write(&mut self, words: &[u8]) -> Result<(), Self::Error> {
+            self.written[self.len..self.len + words.len()].copy_from_slice(words);
+            self.len += words.len();
+            Ok(())
+        }
+    }
+
+    // 16x8 panel: BUFFER_SIZE = 16, DIRTY_SIZE = 1, REFRESH_SIZE = 16 + 1 + 8*2 + 1.
+    type TestDisplay = Display<MockSpi, NoPin, NoPin, 16, 8, 16, 1, 34>;
+
+    fn new_display() -> TestDisplay {
+        TestDisplay::new(MockSpi::new(), NoPin).unwrap()
+    }
+
+    #[test]
+    fn byte_mask_covers_requested_bits_only() {
+        assert_eq!(byte_mask(0, 3), 0b0000_0111);
+        assert_eq!(byte_mask(3, 5), 0b1111_1000);
+        assert_eq!(byte_mask(0, 8), 0xFF);
+    }
+
+    #[test]
+    fn dirty_bit_round_trips() {
+        let mut display = new_display();
+        assert!(!display.is_dirty(3));
+        display.mark_dirty(3);
+        assert!(display.is_dirty(3));
+        assert!(!display.is_dirty(2));
+    }
+
+    #[test]
+    fn fill_region_within_single_byte() {
+        let mut display = new_display();
+        display.fill_region(2, 0, 3, 1, true);
+        assert_eq!(display.buffer[0], 0b0001_1100);
+    }
+
+    #[test]
+    fn fill_region_spans_multiple_bytes() {
+        let mut display = new_display();
+        display.fill_region(4, 0, 8, 1, true);
+        assert_eq!(display.buffer[0], 0b1111_0000);
+        assert_eq!(display.buffer[1], 0b0000_1111);
+    }
+
+    #[test]
+    fn refresh_only_transmits_dirty_lines() {
+        let mut display = new_display();
+        display.com = MockSpi::new(); // discard the writes made by new()'s initial clear()
+        display.set_pixel(0, 3, true);
+        display.refresh().unwrap();
+
+        // command byte + (line number, 2 bytes of data, trailing byte) + final terminator
+        assert_eq!(display.com.len, 1 + (1 + 2 + 1) + 1);
+        assert_eq!(display.com.written[1], 4); // line addresses are 1-indexed
+        assert!(!display.is_dirty(3));
+    }
+}